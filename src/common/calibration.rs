@@ -0,0 +1,80 @@
+// Copyright 2019-present, the HuggingFace Inc. team, The Google AI Language Team and Facebook, Inc.
+// Copyright 2019 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// # Score calibration
+///
+/// Raw scores coming out of a model (QA span scores, embedding similarities) are hard to
+/// threshold because their distribution shifts with the model and the data. A
+/// `CalibrationConfig` remaps a raw score through a shifted logistic so that scores spread
+/// across `[0, 1]` around a meaningful midpoint, making a fixed acceptance threshold (e.g.
+/// `0.5`) behave consistently across models.
+///
+/// The transform is applied to an already-computed score, so calibrating or re-calibrating
+/// `m` and `sigma` after measuring a score distribution never requires re-running inference.
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationConfig {
+    /// Mean of the raw score distribution around which scores are centered
+    pub m: f64,
+    /// Spread of the raw score distribution; smaller values make the transform steeper
+    pub sigma: f64,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> CalibrationConfig {
+        CalibrationConfig { m: 0.0, sigma: 1.0 }
+    }
+}
+
+impl CalibrationConfig {
+    pub fn new(m: f64, sigma: f64) -> CalibrationConfig {
+        CalibrationConfig { m, sigma }
+    }
+
+    /// Remap a raw score `s` to `1 / (1 + exp(-(s - m) / sigma))`
+    pub fn calibrate(&self, score: f64) -> f64 {
+        1f64 / (1f64 + (-(score - self.m) / self.sigma).exp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibrate_maps_the_mean_to_one_half() {
+        let calibration = CalibrationConfig::new(0.5, 0.1);
+        assert!((calibration.calibrate(0.5) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn calibrate_stays_within_unit_range() {
+        let calibration = CalibrationConfig::new(0.0, 1.0);
+        for score in [-100.0, -1.0, 0.0, 1.0, 100.0] {
+            let calibrated = calibration.calibrate(score);
+            assert!(calibrated > 0.0 && calibrated < 1.0);
+        }
+    }
+
+    #[test]
+    fn calibrate_is_monotonically_increasing_in_score() {
+        let calibration = CalibrationConfig::new(0.0, 1.0);
+        assert!(calibration.calibrate(1.0) > calibration.calibrate(0.0));
+        assert!(calibration.calibrate(0.0) > calibration.calibrate(-1.0));
+    }
+
+    #[test]
+    fn smaller_sigma_makes_the_transform_steeper() {
+        let narrow = CalibrationConfig::new(0.0, 0.1);
+        let wide = CalibrationConfig::new(0.0, 10.0);
+        assert!(narrow.calibrate(1.0) > wide.calibrate(1.0));
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2019-present, the HuggingFace Inc. team, The Google AI Language Team and Facebook, Inc.
+// Copyright 2019 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// # Configuration for a `MicroBatcher`
+#[derive(Debug, Clone, Copy)]
+pub struct MicroBatcherConfig {
+    /// Largest number of requests folded into a single forward pass
+    pub max_batch_size: usize,
+    /// Longest time a request waits for more requests to join its batch before the batch
+    /// is run as-is; keeps latency bounded under sparse traffic
+    pub max_wait: Duration,
+}
+
+impl Default for MicroBatcherConfig {
+    fn default() -> MicroBatcherConfig {
+        MicroBatcherConfig { max_batch_size: 16, max_wait: Duration::from_millis(5) }
+    }
+}
+
+/// Slot shared between a `SubmitFuture` and the worker thread that will eventually fill it in
+struct ResponseSlot<Resp> {
+    response: Option<Resp>,
+    waker: Option<Waker>,
+}
+
+/// Future returned by `MicroBatcher::submit`, resolving once the worker thread has run the
+/// batch this request was folded into and scattered back its response
+pub struct SubmitFuture<Resp> {
+    slot: Arc<Mutex<ResponseSlot<Resp>>>,
+}
+
+impl<Resp> Future for SubmitFuture<Resp> {
+    type Output = Resp;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Resp> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.response.take() {
+            Some(response) => Poll::Ready(response),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// # MicroBatcher
+///
+/// Accumulates individually-submitted requests arriving within `max_wait` of the first one
+/// in a batch, up to `max_batch_size`, then runs them through a single user-supplied
+/// `batch_fn` call and scatters each result back to its caller's `SubmitFuture`, waking it on
+/// whatever executor is polling it. This amortizes per-call tensor setup and device launch
+/// overhead across concurrent callers, at the cost of adding up to `max_wait` of latency to a
+/// request that arrives alone - unlike a thread-blocking `submit`, a waiting request parks an
+/// executor task rather than a whole OS thread, which is what makes this viable under
+/// concurrent load from an async server.
+///
+/// `MicroBatcher` is cheap to clone: cloning only duplicates the channel handle used to
+/// submit requests to the single background worker thread.
+pub struct MicroBatcher<Req, Resp> {
+    sender: mpsc::Sender<(Req, Arc<Mutex<ResponseSlot<Resp>>>)>,
+}
+
+impl<Req, Resp> Clone for MicroBatcher<Req, Resp> {
+    fn clone(&self) -> MicroBatcher<Req, Resp> {
+        MicroBatcher { sender: self.sender.clone() }
+    }
+}
+
+impl<Req: Send + 'static, Resp: Send + 'static> MicroBatcher<Req, Resp> {
+    /// Spawn the background worker thread and return a handle that can be shared across callers
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Max batch size and max wait knobs
+    /// * `batch_fn` - Runs one forward pass over an accumulated batch, returning one response
+    ///   per request in the same order
+    pub fn new<F>(config: MicroBatcherConfig, batch_fn: F) -> MicroBatcher<Req, Resp>
+        where F: Fn(Vec<Req>) -> Vec<Resp> + Send + 'static {
+        let (sender, receiver) = mpsc::channel::<(Req, Arc<Mutex<ResponseSlot<Resp>>>)>();
+
+        thread::spawn(move || {
+            while let Ok(first) = receiver.recv() {
+                let mut requests = vec![first];
+                let deadline = Instant::now() + config.max_wait;
+
+                while requests.len() < config.max_batch_size {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        break;
+                    }
+                    match receiver.recv_timeout(remaining) {
+                        Ok(next) => requests.push(next),
+                        Err(_) => break,
+                    }
+                }
+
+                let (inputs, slots): (Vec<Req>, Vec<Arc<Mutex<ResponseSlot<Resp>>>>) = requests.into_iter().unzip();
+                let responses = batch_fn(inputs);
+                for (slot, response) in slots.into_iter().zip(responses.into_iter()) {
+                    let waker = {
+                        let mut slot = slot.lock().unwrap();
+                        slot.response = Some(response);
+                        slot.waker.take()
+                    };
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+        });
+
+        MicroBatcher { sender }
+    }
+
+    /// Submit a single request and return a `Future` that resolves once its response has been
+    /// scattered back, without blocking the calling thread while it waits for the batch to
+    /// fill. Await it directly from any executor (tokio, async-std, a manual `block_on`, ...).
+    ///
+    /// Note: if `batch_fn` panics partway through a batch, the `SubmitFuture`s for that
+    /// batch's requests are never woken and will not resolve; a production caller typically
+    /// wraps the await in a timeout.
+    ///
+    /// # Panics
+    ///
+    /// Panics immediately if the background worker thread has already terminated.
+    pub fn submit(&self, request: Req) -> SubmitFuture<Resp> {
+        let slot = Arc::new(Mutex::new(ResponseSlot { response: None, waker: None }));
+        self.sender.send((request, slot.clone())).expect("micro-batcher worker thread has stopped");
+        SubmitFuture { slot }
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright 2019-present, the HuggingFace Inc. team, The Google AI Language Team and Facebook, Inc.
+// Copyright 2019 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// # Embedder
+///
+/// Common interface for anything that turns sentences/passages into fixed-size vectors.
+/// `EmbeddingModel` (DistilBert) is the first concrete implementation; a full BERT backbone
+/// or any future architecture can implement the same trait so that retrieval code, and any
+/// other call site, can be written against `&dyn Embedder` instead of a specific model. A
+/// single process can also host several named embedders behind this trait, e.g. for A/B
+/// comparison or mixing a small/fast model with a larger/higher-quality one.
+pub trait Embedder {
+    /// Embed a batch of sentences/passages into fixed-size vectors, in the same order as `input`
+    fn embed(&self, input: &[String]) -> Vec<Vec<f32>>;
+
+    /// Dimensionality of the vectors returned by `embed`
+    fn dimensions(&self) -> i64;
+
+    /// Maximum number of tokens considered per input; longer inputs are truncated
+    fn max_tokens(&self) -> usize;
+}
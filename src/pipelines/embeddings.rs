@@ -0,0 +1,249 @@
+// Copyright 2019-present, the HuggingFace Inc. team, The Google AI Language Team and Facebook, Inc.
+// Copyright 2019 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use tch::{nn, Device, Kind, Tensor};
+use rust_tokenizers::BertTokenizer;
+use rust_tokenizers::preprocessing::tokenizer::base_tokenizer::{Tokenizer, TruncationStrategy};
+
+use crate::common::calibration::CalibrationConfig;
+use crate::distilbert::distilbert::{DistilBertConfig, DistilBertModel};
+use crate::pipelines::batching::{MicroBatcher, MicroBatcherConfig};
+use crate::pipelines::embedder::Embedder;
+
+/// Batch size used by the `Embedder::embed` trait method, which has no batch size parameter
+/// of its own. Call `EmbeddingModel::predict` directly to control batching explicitly.
+const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// # How the last hidden state is collapsed into a single vector
+#[derive(Debug, Clone, Copy)]
+pub enum PoolingStrategy {
+    /// Average the non-padding token representations
+    Mean,
+    /// Use the representation of the first (`[CLS]`) token
+    Cls,
+    /// Take the element-wise maximum over the non-padding token representations
+    Max,
+}
+
+/// # Configuration for the `EmbeddingModel`
+pub struct EmbeddingConfig {
+    /// Strategy used to pool the token-level hidden states into a sentence vector
+    pub pooling: PoolingStrategy,
+    /// Re-scale the pooled vector to unit (L2) norm
+    pub normalize: bool,
+    /// Maximum number of tokens kept per input (longer inputs are truncated)
+    pub max_length: usize,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> EmbeddingConfig {
+        EmbeddingConfig {
+            pooling: PoolingStrategy::Mean,
+            normalize: true,
+            max_length: 128,
+        }
+    }
+}
+
+/// # EmbeddingModel to produce fixed-size sentence/passage vectors
+///
+/// Reuses the DistilBert embeddings and transformer stack used for question
+/// answering, but pools the last hidden state into a single vector per input
+/// instead of feeding it to a task-specific head.
+pub struct EmbeddingModel {
+    tokenizer: BertTokenizer,
+    distilbert_model: DistilBertModel,
+    var_store: nn::VarStore,
+    config: EmbeddingConfig,
+    dim: i64,
+    /// When set, remaps `similarity` scores through a shifted logistic so that a fixed
+    /// acceptance threshold behaves consistently regardless of the embedding model
+    pub calibration: Option<CalibrationConfig>,
+}
+
+impl EmbeddingModel {
+    /// Build a new `EmbeddingModel`
+    ///
+    /// # Arguments
+    ///
+    /// * `vocab_path` - Path to the model vocabulary, expected to have a structure as per the associated `BertTokenizer`
+    /// * `config_path` - Path to the model configuration, expected to have a structure as per the `DistilBertConfig`
+    /// * `weights_path` - Path to the model weight files, expected to have a structure as per the `DistilBertModel`
+    /// * `device` - Device to run the model on, e.g. `Device::Cpu` or `Device::Cuda(0)`
+    /// * `embedding_config` - Pooling and normalization settings for the produced vectors
+    pub fn new(vocab_path: &Path,
+               config_path: &Path,
+               weights_path: &Path,
+               device: Device,
+               embedding_config: EmbeddingConfig)
+               -> failure::Fallible<EmbeddingModel> {
+        let tokenizer = BertTokenizer::from_file(vocab_path.to_str().unwrap(), true);
+        let mut var_store = nn::VarStore::new(device);
+        let config = DistilBertConfig::from_file(config_path);
+        let dim = config.dim;
+        let distilbert_model = DistilBertModel::new(&var_store.root(), &config);
+        var_store.load(weights_path)?;
+        Ok(EmbeddingModel { tokenizer, distilbert_model, var_store, config: embedding_config, dim, calibration: None })
+    }
+
+    fn encode(&self, input: &[String]) -> (Tensor, Tensor) {
+        let tokenized_input = self.tokenizer.encode_list(&input.to_vec(),
+                                                          self.config.max_length,
+                                                          &TruncationStrategy::LongestFirst,
+                                                          0);
+        let max_len = tokenized_input.iter().map(|input| input.token_ids.len()).max().unwrap_or(0);
+        let pad_id = 0i64;
+        let mut attention_masks: Vec<Tensor> = Vec::with_capacity(tokenized_input.len());
+        let token_ids: Vec<Tensor> = tokenized_input.
+            into_iter().
+            map(|input| {
+                let mut token_ids = input.token_ids;
+                let mut mask = vec![1i64; token_ids.len()];
+                mask.extend(vec![0i64; max_len - token_ids.len()]);
+                token_ids.extend(vec![pad_id; max_len - token_ids.len()]);
+                attention_masks.push(Tensor::of_slice(&mask));
+                Tensor::of_slice(&token_ids)
+            }).
+            collect();
+        (Tensor::stack(&token_ids, 0).to_device(self.var_store.device()),
+         Tensor::stack(&attention_masks, 0).to_device(self.var_store.device()))
+    }
+
+    /// Embed a batch of sentences/passages into fixed-size vectors
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Sentences or passages to embed
+    /// * `batch_size` - Number of inputs processed per forward pass
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<f32>>` containing one embedding vector per input, in the same order
+    pub fn predict(&self, input: &[String], batch_size: usize) -> Vec<Vec<f32>> {
+        let mut output = Vec::with_capacity(input.len());
+        for batch in input.chunks(batch_size.max(1)) {
+            let (token_ids, attention_mask) = self.encode(batch);
+            let (hidden_state, _, _) = tch::no_grad(|| {
+                self.distilbert_model
+                    .forward_t(Some(token_ids), Some(attention_mask.copy()), None, false)
+                    .unwrap()
+            });
+            let mut pooled = pool(&hidden_state, &attention_mask, self.config.pooling);
+            if self.config.normalize {
+                let norm = pooled.norm2(2, &[-1], true).clamp_min(1e-12);
+                pooled = pooled / norm;
+            }
+            for i in 0..pooled.size()[0] {
+                output.push(Vec::<f32>::from(pooled.get(i)));
+            }
+        }
+        output
+    }
+
+    /// Dot-product similarity between two (expected L2-normalized) embeddings, remapped
+    /// through `self.calibration` if set so a fixed acceptance threshold behaves
+    /// consistently regardless of the underlying model
+    pub fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        let score: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+        match &self.calibration {
+            Some(calibration) => calibration.calibrate(score as f64) as f32,
+            None => score,
+        }
+    }
+}
+
+/// Collapse `hidden_state` (`batch x seq_len x dim`) into one vector per sequence
+/// (`batch x dim`) according to `strategy`, using `attention_mask` (`batch x seq_len`) to
+/// ignore padded positions for `Mean` and `Max`
+fn pool(hidden_state: &Tensor, attention_mask: &Tensor, strategy: PoolingStrategy) -> Tensor {
+    match strategy {
+        PoolingStrategy::Cls => hidden_state.select(1, 0),
+        PoolingStrategy::Mean => {
+            let mask = attention_mask.unsqueeze(-1).to_kind(Kind::Float);
+            let summed = (hidden_state * &mask).sum1(&[1], false, Kind::Float);
+            let counts = mask.sum1(&[1], false, Kind::Float).clamp_min(1e-9);
+            summed / counts
+        }
+        PoolingStrategy::Max => {
+            let mask = attention_mask.unsqueeze(-1).to_kind(Kind::Float);
+            let masked = hidden_state + (mask - 1).clamp_min(-1e4) * 1e4;
+            masked.max1(1, false).0
+        }
+    }
+}
+
+/// Wrap an `EmbeddingModel` behind a `MicroBatcher` so that individually-submitted sentences,
+/// arriving concurrently from multiple callers, are folded into shared forward passes
+///
+/// # Arguments
+///
+/// * `embedding_model` - Model used to run each accumulated batch
+/// * `config` - Max batch size and max wait knobs, see `MicroBatcherConfig`
+pub fn micro_batched(embedding_model: EmbeddingModel, config: MicroBatcherConfig) -> MicroBatcher<String, Vec<f32>> {
+    let embedding_model = Arc::new(embedding_model);
+    MicroBatcher::new(config, move |batch: Vec<String>| embedding_model.predict(&batch, batch.len()))
+}
+
+impl Embedder for EmbeddingModel {
+    fn embed(&self, input: &[String]) -> Vec<Vec<f32>> {
+        self.predict(input, DEFAULT_BATCH_SIZE)
+    }
+
+    fn dimensions(&self) -> i64 {
+        self.dim
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.config.max_length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single sequence, batch size 1, seq_len 3, dim 2, where the last position is padded
+    /// and carries values (100.0) that must not leak into a mean/max pool over real tokens
+    fn sample_hidden_state() -> (Tensor, Tensor) {
+        let hidden_state = Tensor::of_slice(&[1.0f32, 2.0, 3.0, 4.0, 100.0, 100.0]).view([1, 3, 2]);
+        let attention_mask = Tensor::of_slice(&[1i64, 1, 0]).view([1, 3]);
+        (hidden_state, attention_mask)
+    }
+
+    fn to_vec(tensor: &Tensor) -> Vec<f32> {
+        Vec::<f32>::from(tensor.view([-1]))
+    }
+
+    #[test]
+    fn cls_pooling_takes_the_first_token() {
+        let (hidden_state, attention_mask) = sample_hidden_state();
+        let pooled = pool(&hidden_state, &attention_mask, PoolingStrategy::Cls);
+        assert_eq!(to_vec(&pooled), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn mean_pooling_ignores_padded_positions() {
+        let (hidden_state, attention_mask) = sample_hidden_state();
+        let pooled = pool(&hidden_state, &attention_mask, PoolingStrategy::Mean);
+        assert_eq!(to_vec(&pooled), vec![2.0, 3.0]);
+    }
+
+    #[test]
+    fn max_pooling_ignores_padded_positions() {
+        let (hidden_state, attention_mask) = sample_hidden_state();
+        let pooled = pool(&hidden_state, &attention_mask, PoolingStrategy::Max);
+        assert_eq!(to_vec(&pooled), vec![3.0, 4.0]);
+    }
+}
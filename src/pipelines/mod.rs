@@ -0,0 +1,23 @@
+// Copyright 2019-present, the HuggingFace Inc. team, The Google AI Language Team and Facebook, Inc.
+// Copyright 2019 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Ready-to-use NLP pipelines
+//!
+//! Building blocks that wrap a trained model and a tokenizer together behind a
+//! single `predict` entry point, so consumers do not need to manage tensors,
+//! padding or post-processing themselves.
+
+pub mod question_answering;
+pub mod embedder;
+pub mod embeddings;
+pub mod retrieval;
+pub mod batching;
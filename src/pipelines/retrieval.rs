@@ -0,0 +1,437 @@
+// Copyright 2019-present, the HuggingFace Inc. team, The Google AI Language Team and Facebook, Inc.
+// Copyright 2019 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::common::calibration::CalibrationConfig;
+use crate::pipelines::embedder::Embedder;
+use crate::pipelines::question_answering::{QaInput, QuestionAnsweringModel, Answer};
+
+/// # A chunk of a source document stored in a `DocumentIndex`
+pub struct Chunk {
+    /// Identifier of the document this chunk was extracted from
+    pub doc_id: String,
+    /// Character offsets (not byte offsets) of this chunk within the original document -
+    /// the same unit `Answer::start`/`end` are expressed in, so the two can be summed
+    /// directly when merging an answer span back to document offsets
+    pub char_range: (usize, usize),
+    /// Chunk text, passed as context to the QA model when retrieved
+    pub text: String,
+    /// L2-normalized embedding vector of `text`
+    pub vector: Vec<f32>,
+    /// Lower-cased whitespace tokens of `text`, used for BM25 lexical scoring
+    terms: Vec<String>,
+}
+
+/// A chunk retrieved for a query, together with its similarity score
+pub struct RetrievedChunk<'a> {
+    pub chunk: &'a Chunk,
+    pub score: f32,
+}
+
+/// A chunk retrieved through `DocumentIndex::top_k_hybrid`, keeping the lexical and dense
+/// sub-scores alongside the fused score so that ranking decisions can be inspected.
+pub struct HybridRetrievedChunk<'a> {
+    pub chunk: &'a Chunk,
+    /// Min-max normalized dense (embedding) similarity, in `[0, 1]`
+    pub dense_score: f32,
+    /// Min-max normalized BM25 lexical score, in `[0, 1]`
+    pub lexical_score: f32,
+    /// `semantic_ratio * dense_score + (1 - semantic_ratio) * lexical_score`
+    pub score: f32,
+}
+
+/// An answer produced from a retrieved chunk, with the chunk's provenance attached
+pub struct RetrievedAnswer {
+    pub answer: Answer,
+    pub doc_id: String,
+    pub char_range: (usize, usize),
+}
+
+/// # DocumentIndex
+///
+/// Splits documents into chunks that fit within a model's maximum position
+/// embeddings, embeds each chunk and keeps the resulting vectors in memory so
+/// that a `QuestionAnsweringModel` can be queried over a full corpus instead
+/// of a single pre-selected context.
+pub struct DocumentIndex {
+    chunks: Vec<Chunk>,
+    max_chunk_length: usize,
+    /// When set, remaps the raw dot-product scores returned by `top_k` through a shifted
+    /// logistic so that a fixed acceptance threshold behaves consistently regardless of the
+    /// embedding model. Does NOT apply to `top_k_hybrid`: its dense/lexical/fused scores are
+    /// already min-max normalized to `[0, 1]`, and `m`/`sigma` tuned against a raw dot-product
+    /// distribution would not be meaningful against an already-rescaled one.
+    pub calibration: Option<CalibrationConfig>,
+}
+
+impl DocumentIndex {
+    /// Create an empty index
+    ///
+    /// # Arguments
+    ///
+    /// * `max_chunk_length` - Maximum number of characters per chunk, kept comfortably under
+    ///   the embedding model's `max_position_embeddings` after tokenization
+    pub fn new(max_chunk_length: usize) -> DocumentIndex {
+        DocumentIndex { chunks: Vec::new(), max_chunk_length, calibration: None }
+    }
+
+    fn calibrate(&self, score: f32) -> f32 {
+        match &self.calibration {
+            Some(calibration) => calibration.calibrate(score as f64) as f32,
+            None => score,
+        }
+    }
+
+    /// Split `text` on whitespace boundaries into chunks no longer than `max_chunk_length`
+    /// characters, embed each chunk and add it to the index under `doc_id`.
+    pub fn add_document(&mut self, doc_id: &str, text: &str, embedding_model: &dyn Embedder) {
+        let boundaries = split_into_chunks(text, self.max_chunk_length);
+        let chunk_texts: Vec<String> = boundaries.iter()
+            .map(|boundary| text[boundary.byte_range.0..boundary.byte_range.1].to_owned())
+            .collect();
+        let vectors = embedding_model.embed(&chunk_texts);
+        for (boundary, (chunk_text, vector)) in boundaries.into_iter()
+            .zip(chunk_texts.into_iter().zip(vectors.into_iter())) {
+            let terms = tokenize(&chunk_text);
+            self.chunks.push(Chunk { doc_id: doc_id.to_owned(), char_range: boundary.char_range, text: chunk_text, vector, terms });
+        }
+    }
+
+    /// Return the `top_k` chunks with the highest dot-product similarity to `query_vector`
+    ///
+    /// Chunk vectors and `query_vector` are expected to already be L2-normalized, so the
+    /// dot product is equivalent to cosine similarity.
+    pub fn top_k(&self, query_vector: &[f32], top_k: usize) -> Vec<RetrievedChunk> {
+        let mut scored: Vec<RetrievedChunk> = self.chunks.iter()
+            .map(|chunk| RetrievedChunk { chunk, score: dot(query_vector, &chunk.vector) })
+            .collect();
+        scored.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        scored.truncate(top_k);
+        for retrieved_chunk in &mut scored {
+            retrieved_chunk.score = self.calibrate(retrieved_chunk.score);
+        }
+        scored
+    }
+
+    /// Retrieve the `top_k` chunks by a fusion of BM25 lexical score and dense similarity
+    ///
+    /// Both score lists are independently min-max normalized to `[0, 1]` before being
+    /// combined as `semantic_ratio * dense_norm + (1 - semantic_ratio) * lexical_norm`, so
+    /// that paraphrased queries (favoured by the dense signal) and queries sharing
+    /// vocabulary with a passage (favoured by BM25) are both handled well. Unlike `top_k`,
+    /// the returned scores are never passed through `self.calibration` - see the field's doc
+    /// comment for why that isn't meaningful here.
+    ///
+    /// # Arguments
+    ///
+    /// * `query_vector` - L2-normalized embedding of the query
+    /// * `query_text` - Raw query text, tokenized the same way as indexed chunks
+    /// * `semantic_ratio` - Weight given to the dense score, in `[0, 1]`
+    /// * `top_k` - Number of chunks to return
+    pub fn top_k_hybrid(&self, query_vector: &[f32], query_text: &str, semantic_ratio: f32, top_k: usize) -> Vec<HybridRetrievedChunk> {
+        let semantic_ratio = semantic_ratio.max(0.0).min(1.0);
+        let query_terms = tokenize(query_text);
+
+        let dense_scores: Vec<f32> = self.chunks.iter().map(|chunk| dot(query_vector, &chunk.vector)).collect();
+        let lexical_scores = bm25_scores(&self.chunks, &query_terms);
+
+        let dense_norm = min_max_normalize(&dense_scores);
+        let lexical_norm = min_max_normalize(&lexical_scores);
+
+        let mut scored: Vec<HybridRetrievedChunk> = self.chunks.iter().enumerate()
+            .map(|(i, chunk)| {
+                let dense_score = dense_norm[i];
+                let lexical_score = lexical_norm[i];
+                let score = semantic_ratio * dense_score + (1.0 - semantic_ratio) * lexical_score;
+                HybridRetrievedChunk { chunk, dense_score, lexical_score, score }
+            })
+            .collect();
+        scored.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        scored.truncate(top_k);
+        scored
+    }
+}
+
+/// Score every chunk against `query_terms` using Okapi BM25 (`k1 = 1.5`, `b = 0.75`)
+fn bm25_scores(chunks: &[Chunk], query_terms: &[String]) -> Vec<f32> {
+    const K1: f32 = 1.5;
+    const B: f32 = 0.75;
+
+    let doc_count = chunks.len().max(1) as f32;
+    let avg_len = chunks.iter().map(|chunk| chunk.terms.len()).sum::<usize>() as f32 / doc_count;
+
+    // Computed once per unique query term up front, rather than rescanning every chunk's
+    // terms inside the per-chunk loop below: that used to make this function O(chunks^2 *
+    // query_terms), which is exactly the corpus-level path this retriever exists for.
+    let mut document_frequencies: HashMap<&str, f32> = HashMap::new();
+    for term in query_terms {
+        document_frequencies.entry(term.as_str()).or_insert_with(|| {
+            chunks.iter().filter(|chunk| chunk.terms.iter().any(|t| t == term)).count() as f32
+        });
+    }
+
+    chunks.iter().map(|chunk| {
+        let chunk_len = chunk.terms.len() as f32;
+        query_terms.iter().map(|term| {
+            let term_freq = chunk.terms.iter().filter(|t| *t == term).count() as f32;
+            if term_freq == 0.0 {
+                return 0.0;
+            }
+            let df = document_frequencies[term.as_str()];
+            let idf = ((doc_count - df + 0.5) / (df + 0.5) + 1.0).ln();
+            idf * (term_freq * (K1 + 1.0)) / (term_freq + K1 * (1.0 - B + B * chunk_len / avg_len.max(1.0)))
+        }).sum()
+    }).collect()
+}
+
+/// Rescale `values` to `[0, 1]`; a constant input maps to all zeros
+fn min_max_normalize(values: &[f32]) -> Vec<f32> {
+    let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let range = max - min;
+    values.iter().map(|&v| if range > 1e-9 { (v - min) / range } else { 0.0 }).collect()
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split_whitespace().map(|token| token.to_lowercase()).collect()
+}
+
+/// # Retriever
+///
+/// Ties a `DocumentIndex` to an `Embedder` and a `QuestionAnsweringModel`: embeds the
+/// incoming question, retrieves the most relevant chunks and runs span extraction against
+/// each of them, mapping the resulting answer spans back to document offsets.
+pub struct Retriever<'a> {
+    pub index: &'a DocumentIndex,
+    pub embedding_model: &'a dyn Embedder,
+    pub qa_model: &'a QuestionAnsweringModel,
+    /// When set, remaps returned answer scores through a shifted logistic so that a fixed
+    /// acceptance threshold behaves consistently regardless of the underlying model
+    pub calibration: Option<CalibrationConfig>,
+}
+
+impl<'a> Retriever<'a> {
+    pub fn new(index: &'a DocumentIndex, embedding_model: &'a dyn Embedder, qa_model: &'a QuestionAnsweringModel) -> Retriever<'a> {
+        Retriever { index, embedding_model, qa_model, calibration: None }
+    }
+
+    fn calibrate_answers(&self, mut answers: Vec<RetrievedAnswer>) -> Vec<RetrievedAnswer> {
+        if let Some(calibration) = &self.calibration {
+            for retrieved_answer in &mut answers {
+                retrieved_answer.answer.score = calibration.calibrate(retrieved_answer.answer.score);
+            }
+        }
+        answers
+    }
+
+    /// Answer `question` against the full corpus held in `self.index`
+    ///
+    /// # Arguments
+    ///
+    /// * `question` - Natural language question
+    /// * `top_k` - Number of chunks retrieved as candidate contexts
+    /// * `qa_top_k` - Number of answer spans requested per candidate context
+    pub fn predict(&self, question: &str, top_k: usize, qa_top_k: i64) -> Vec<RetrievedAnswer> {
+        let query_vector = self.embedding_model.embed(&[question.to_owned()])
+            .into_iter().next().unwrap_or_default();
+        let retrieved = self.index.top_k(&query_vector, top_k);
+
+        let qa_inputs: Vec<QaInput> = retrieved.iter()
+            .map(|retrieved_chunk| QaInput { question: question.to_owned(), context: retrieved_chunk.chunk.text.clone() })
+            .collect();
+        let answers = self.qa_model.predict(&qa_inputs, qa_top_k, 32);
+
+        let answers = retrieved.iter().zip(answers.into_iter())
+            .flat_map(|(retrieved_chunk, chunk_answers)| {
+                let doc_id = retrieved_chunk.chunk.doc_id.clone();
+                let (chunk_start, _) = retrieved_chunk.chunk.char_range;
+                chunk_answers.into_iter().map(move |answer| {
+                    let char_range = (chunk_start + answer.start, chunk_start + answer.end);
+                    RetrievedAnswer { answer, doc_id: doc_id.clone(), char_range }
+                })
+            })
+            .collect();
+        self.calibrate_answers(answers)
+    }
+
+    /// Answer `question` against the full corpus, ranking candidate contexts with the hybrid
+    /// lexical + dense fusion from `DocumentIndex::top_k_hybrid` instead of dense-only retrieval
+    pub fn predict_hybrid(&self, question: &str, semantic_ratio: f32, top_k: usize, qa_top_k: i64) -> Vec<RetrievedAnswer> {
+        let query_vector = self.embedding_model.embed(&[question.to_owned()])
+            .into_iter().next().unwrap_or_default();
+        let retrieved = self.index.top_k_hybrid(&query_vector, question, semantic_ratio, top_k);
+
+        let qa_inputs: Vec<QaInput> = retrieved.iter()
+            .map(|retrieved_chunk| QaInput { question: question.to_owned(), context: retrieved_chunk.chunk.text.clone() })
+            .collect();
+        let answers = self.qa_model.predict(&qa_inputs, qa_top_k, 32);
+
+        let answers = retrieved.iter().zip(answers.into_iter())
+            .flat_map(|(retrieved_chunk, chunk_answers)| {
+                let doc_id = retrieved_chunk.chunk.doc_id.clone();
+                let (chunk_start, _) = retrieved_chunk.chunk.char_range;
+                chunk_answers.into_iter().map(move |answer| {
+                    let char_range = (chunk_start + answer.start, chunk_start + answer.end);
+                    RetrievedAnswer { answer, doc_id: doc_id.clone(), char_range }
+                })
+            })
+            .collect();
+        self.calibrate_answers(answers)
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// A split point produced by `split_into_chunks`: `byte_range` is valid for slicing the
+/// source `text` (`str` indexing requires byte offsets), `char_range` is the same span
+/// expressed in characters, matching the unit `Chunk::char_range` and `Answer::start`/`end`
+/// use. The two only coincide for all-ASCII text, so both are tracked explicitly.
+struct ChunkBoundary {
+    byte_range: (usize, usize),
+    char_range: (usize, usize),
+}
+
+/// Split `text` into chunks of at most `max_chunk_length` characters, breaking on whitespace
+/// so that no chunk splits a word in half. A word of `max_chunk_length` characters or more
+/// has no whitespace to break on and is cut at the character limit.
+fn split_into_chunks(text: &str, max_chunk_length: usize) -> Vec<ChunkBoundary> {
+    let mut chunks = Vec::new();
+    let mut chunk_start = (0usize, 0usize);
+    let mut last_boundary = (0usize, 0usize);
+    let mut char_index = 0usize;
+    for (byte_index, character) in text.char_indices() {
+        if character.is_whitespace() {
+            last_boundary = (byte_index, char_index);
+        }
+        if char_index - chunk_start.1 >= max_chunk_length {
+            let end = if last_boundary.1 > chunk_start.1 { last_boundary } else { (byte_index, char_index) };
+            chunks.push(ChunkBoundary { byte_range: (chunk_start.0, end.0), char_range: (chunk_start.1, end.1) });
+            chunk_start = end;
+            last_boundary = end;
+        }
+        char_index += 1;
+    }
+    if chunk_start.0 < text.len() {
+        chunks.push(ChunkBoundary { byte_range: (chunk_start.0, text.len()), char_range: (chunk_start.1, char_index) });
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_with_terms(text: &str, terms: &[&str]) -> Chunk {
+        Chunk {
+            doc_id: "doc".to_owned(),
+            char_range: (0, text.chars().count()),
+            text: text.to_owned(),
+            vector: Vec::new(),
+            terms: terms.iter().map(|term| term.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn split_into_chunks_keeps_byte_and_char_ranges_in_their_own_units() {
+        // "café" is 4 characters but 5 bytes (the "é" is a 2-byte UTF-8 sequence), so a
+        // byte/char mix-up here would point a non-ASCII "char_range" at the wrong substring.
+        let text = "café noir, café au lait";
+        let boundaries = split_into_chunks(text, 10);
+
+        for boundary in &boundaries {
+            let by_byte = &text[boundary.byte_range.0..boundary.byte_range.1];
+            let char_len = boundary.char_range.1 - boundary.char_range.0;
+            assert_eq!(by_byte.chars().count(), char_len);
+        }
+
+        let first = &boundaries[0];
+        assert_eq!(&text[first.byte_range.0..first.byte_range.1], "café noir,");
+        assert_eq!(first.char_range, (0, 10));
+    }
+
+    #[test]
+    fn top_k_does_not_panic_on_nan_scores() {
+        let mut index = DocumentIndex::new(100);
+        index.chunks.push(Chunk { doc_id: "doc".to_owned(), char_range: (0, 1), text: "a".to_owned(), vector: vec![1.0, 0.0], terms: vec![] });
+        index.chunks.push(Chunk { doc_id: "doc".to_owned(), char_range: (0, 1), text: "b".to_owned(), vector: vec![0.0, 1.0], terms: vec![] });
+
+        // A zero query vector makes the dot product NaN-free here, so force a NaN directly
+        // through a chunk vector instead - a degenerate embedding (e.g. all-zero input through
+        // a faulty pooling path) is exactly the kind of input that used to panic the sort.
+        index.chunks[0].vector = vec![f32::NAN, f32::NAN];
+
+        let results = index.top_k(&[1.0, 0.0], 2);
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn top_k_hybrid_does_not_panic_on_nan_scores() {
+        let mut index = DocumentIndex::new(100);
+        index.chunks.push(chunk_with_terms("cat cat cat", &["cat", "cat", "cat"]));
+        index.chunks.push(chunk_with_terms("cat dog", &["cat", "dog"]));
+        index.chunks[0].vector = vec![f32::NAN, f32::NAN];
+        index.chunks[1].vector = vec![0.0, 1.0];
+
+        let results = index.top_k_hybrid(&[1.0, 0.0], "cat", 0.5, 2);
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn split_into_chunks_breaks_on_whitespace_not_mid_word() {
+        let text = "one two three four";
+        let boundaries = split_into_chunks(text, 7);
+        let rendered: Vec<&str> = boundaries.iter()
+            .map(|boundary| &text[boundary.byte_range.0..boundary.byte_range.1])
+            .collect();
+        for piece in &rendered {
+            assert!(!piece.is_empty());
+        }
+        for boundary in &boundaries {
+            let rest = &text[boundary.byte_range.1..];
+            assert!(rest.is_empty() || rest.starts_with(char::is_whitespace));
+        }
+        assert_eq!(rendered.join(""), text);
+    }
+
+    #[test]
+    fn min_max_normalize_rescales_to_unit_range() {
+        let normalized = min_max_normalize(&[1.0, 2.0, 4.0]);
+        assert_eq!(normalized, vec![0.0, 1.0 / 3.0, 1.0]);
+    }
+
+    #[test]
+    fn min_max_normalize_maps_constant_input_to_zero() {
+        let normalized = min_max_normalize(&[2.0, 2.0, 2.0]);
+        assert_eq!(normalized, vec![0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn bm25_scores_ranks_higher_term_frequency_above_lower() {
+        let chunks = vec![
+            chunk_with_terms("cat cat cat", &["cat", "cat", "cat"]),
+            chunk_with_terms("cat dog", &["cat", "dog"]),
+        ];
+        let scores = bm25_scores(&chunks, &[String::from("cat")]);
+        assert!(scores[0] > scores[1]);
+    }
+
+    #[test]
+    fn bm25_scores_is_zero_when_query_term_is_absent() {
+        let chunks = vec![chunk_with_terms("cat dog", &["cat", "dog"])];
+        let scores = bm25_scores(&chunks, &[String::from("elephant")]);
+        assert_eq!(scores, vec![0.0]);
+    }
+}